@@ -1,15 +1,19 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::PathBuf;
 
 use libs::ammonia;
 use libs::elasticlunr::{lang, Index, IndexBuilder};
 use libs::once_cell::sync::Lazy;
+use libs::unicode_segmentation::UnicodeSegmentation;
 
 use config::{Config, Search};
 use content::{Library, Section};
 use errors::{bail, Result};
 use libs::ahash::AHashMap;
 
+/// Used when `search.snippet` is enabled but `search.snippet_length` isn't set.
+const DEFAULT_SNIPPET_LENGTH: usize = 150;
+
 pub const ELASTICLUNR_JS: &str = include_str!("elasticlunr.min.js");
 
 static AMMONIA: Lazy<ammonia::Builder<'static>> = Lazy::new(|| {
@@ -30,13 +34,43 @@ static AMMONIA: Lazy<ammonia::Builder<'static>> = Lazy::new(|| {
     builder
 });
 
+// Strips every tag but keeps the text of `pre`/`code` elements, so it can be used to
+// pull the contents of fenced code blocks back out of the rendered HTML.
+static AMMONIA_CODE: Lazy<ammonia::Builder<'static>> = Lazy::new(|| {
+    let mut clean_content = HashSet::new();
+    clean_content.insert("script");
+    clean_content.insert("style");
+    let mut builder = ammonia::Builder::new();
+    builder
+        .tags(HashSet::new())
+        .tag_attributes(HashMap::new())
+        .generic_attributes(HashSet::new())
+        .link_rel(None)
+        .allowed_classes(HashMap::new())
+        .clean_content_tags(clean_content);
+    builder
+});
+
+static CODE_BLOCK_RE: Lazy<libs::regex::Regex> =
+    Lazy::new(|| libs::regex::Regex::new(r"(?is)<pre[^>]*>.*?</pre>").unwrap());
+
 fn build_fields(search_config: &Search, mut index: IndexBuilder) -> IndexBuilder {
     if search_config.include_title {
         index = index.add_field("title");
+    } else if search_config.store_title {
+        // Stored for result cards, but not searched: `include_title` is what makes a
+        // title match a query, this is purely for display.
+        index = index.add_field_with_tokenizer("title", Box::new(no_index_tokenizer));
     }
 
     if search_config.include_description {
         index = index.add_field("description");
+    } else if search_config.store_description {
+        index = index.add_field_with_tokenizer("description", Box::new(no_index_tokenizer));
+    }
+
+    if search_config.store_date {
+        index = index.add_field_with_tokenizer("date", Box::new(no_index_tokenizer));
     }
 
     if search_config.include_path {
@@ -47,6 +81,16 @@ fn build_fields(search_config: &Search, mut index: IndexBuilder) -> IndexBuilder
         index = index.add_field("body");
     }
 
+    if search_config.snippet {
+        // Stored so themes can render a result preview without re-fetching the page,
+        // but not tokenized: it should never affect relevance scoring on its own.
+        index = index.add_field_with_tokenizer("snippet", Box::new(no_index_tokenizer));
+    }
+
+    if search_config.include_code_blocks {
+        index = index.add_field_with_tokenizer("code", Box::new(code_tokenizer));
+    }
+
     if search_config.include_tags {
         index = index.add_field("tags");
     }
@@ -65,10 +109,67 @@ fn path_tokenizer(text: &str) -> Vec<String> {
         .collect()
 }
 
+/// A tokenizer for fields that should be stored on the document but never searched,
+/// e.g. a result snippet shown by themes. Returning no tokens keeps the field out of
+/// the inverted index while elasticlunr still stores its raw value on the document.
+fn no_index_tokenizer(_text: &str) -> Vec<String> {
+    Vec::new()
+}
+
+/// Truncates `text` to at most `length` grapheme clusters, never splitting a grapheme
+/// cluster in half, and prefers to stop at the last whitespace before the cutoff so
+/// words aren't cut in the middle. Counts grapheme clusters like Tera's `truncate`
+/// filter, but appends no ellipsis.
+fn truncate_at_word_boundary(text: &str, length: usize) -> String {
+    let graphemes: Vec<&str> = text.graphemes(true).collect();
+
+    if length >= graphemes.len() {
+        return text.to_string();
+    }
+
+    let result = graphemes[..length].join("");
+    // `length < graphemes.len()` (checked above), so this indexing never panics.
+    if graphemes[length].chars().all(char::is_whitespace) {
+        result.trim_end().to_string()
+    } else {
+        match result.rfind(char::is_whitespace) {
+            Some(idx) => result[..idx].to_string(),
+            None => result,
+        }
+    }
+}
+
+/// Collapses all runs of whitespace (including newlines) into a single space and
+/// trims the ends, so a snippet excerpt reads as a clean single line.
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// A tokenizer for source code: splits on anything that isn't an identifier character,
+/// keeping `_` so `my_function`/`snake_case` stay single tokens, and lowercases so
+/// searches are case-insensitive like the rest of the index.
+fn code_tokenizer(text: &str) -> Vec<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_lowercase())
+        .collect()
+}
+
+/// Pulls the text of every fenced/indented code block (rendered as `<pre>...</pre>`)
+/// out of `content`, for indexing separately from the rest of the body.
+fn extract_code_blocks(content: &str) -> String {
+    CODE_BLOCK_RE
+        .find_iter(content)
+        .map(|block| AMMONIA_CODE.clean(block.as_str()).to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 fn fill_index(
     search_config: &Search,
     title: &Option<String>,
     description: &Option<String>,
+    date: &Option<String>,
     path: &str,
     content: &str,
     categories: Vec<String>,
@@ -76,30 +177,48 @@ fn fill_index(
 ) -> Vec<String> {
     let mut row = vec![];
 
-    if search_config.include_title {
+    if search_config.include_title || search_config.store_title {
         row.push(title.clone().unwrap_or_default());
     }
 
-    if search_config.include_description {
+    if search_config.include_description || search_config.store_description {
         row.push(description.clone().unwrap_or_default());
     }
 
+    if search_config.store_date {
+        row.push(date.clone().unwrap_or_default());
+    }
+
     if search_config.include_path {
         row.push(path.to_string());
     }
 
-    if search_config.include_content {
+    if search_config.include_content || search_config.snippet {
         let body = AMMONIA.clean(content).to_string();
-        if let Some(truncate_len) = search_config.truncate_content_length {
-            // Not great for unicode
-            // TODO: fix it like the truncate in Tera
-            match body.char_indices().nth(truncate_len) {
-                None => row.push(body),
-                Some((idx, _)) => row.push((body[..idx]).to_string()),
-            };
+        // `AMMONIA` drops the `<pre>` tags but keeps their text, so when code blocks
+        // get their own `code` field, remove them from the body text.
+        let body = if search_config.include_code_blocks {
+            AMMONIA.clean(&CODE_BLOCK_RE.replace_all(content, "")).to_string()
         } else {
-            row.push(body);
+            body
         };
+
+        if search_config.include_content {
+            if let Some(truncate_len) = search_config.truncate_content_length {
+                row.push(truncate_at_word_boundary(&body, truncate_len));
+            } else {
+                row.push(body.clone());
+            };
+        }
+
+        if search_config.snippet {
+            let snippet_len = search_config.snippet_length.unwrap_or(DEFAULT_SNIPPET_LENGTH);
+            row.push(truncate_at_word_boundary(&collapse_whitespace(&body), snippet_len));
+        }
+    }
+
+    if search_config.include_code_blocks {
+        row.push(extract_code_blocks(content));
     }
 
     if search_config.include_tags {
@@ -115,42 +234,120 @@ fn fill_index(
     row
 }
 
-/// Returns the generated JSON index with all the documents of the site added using
-/// the language given
+/// One JSON index, scoped either to the whole site (`key` is empty, the default) or,
+/// when `search.split` is on, to a single top-level section (`key` is that section's
+/// path segment). `num_documents` feeds the manifest built by [`build_manifest`].
+pub struct SearchIndexShard {
+    pub key: String,
+    pub json: String,
+    pub num_documents: usize,
+}
+
+/// Returns the generated JSON index, or indexes, with all the documents of the site
+/// added using the language given. With `search.split` off this is a single shard with
+/// an empty key, written as `search_index.<lang>.json` just like before. With it on,
+/// there's one shard per top-level section, meant to be written alongside a manifest
+/// from [`build_manifest`] so clients only have to fetch the shard(s) they need.
 /// Errors if the language given is not available in Elasticlunr
 /// TODO: is making `in_search_index` apply to subsections of a `false` section useful?
-pub fn build_index(lang: &str, library: &Library, config: &Config) -> Result<String> {
-    let language = match lang::from_code(lang) {
-        Some(l) => l,
-        None => {
-            bail!("Tried to build search index for language {} which is not supported", lang);
-        }
+pub fn build_index(lang: &str, library: &Library, config: &Config) -> Result<Vec<SearchIndexShard>> {
+    if lang::from_code(lang).is_none() {
+        bail!("Tried to build search index for language {} which is not supported", lang);
+    }
+    let search_config = &config.languages[lang].search;
+
+    // A shard gets its own `Index`, each needing its own freshly-built field set, so
+    // resolve the language again per shard rather than trying to share one instance.
+    let new_shard = || -> Index {
+        let language = lang::from_code(lang).expect("language was validated above");
+        build_fields(search_config, IndexBuilder::with_language(language)).build()
     };
-    let language_options = &config.languages[lang];
-    let mut index = IndexBuilder::with_language(language);
-    index = build_fields(&language_options.search, index);
-    let mut index = index.build();
+
+    let mut shards: BTreeMap<String, Index> = BTreeMap::new();
+    if !search_config.split {
+        // Seed the whole-site shard up front so a language with no matching sections
+        // yet still gets an (empty) `search_index.<lang>.json`, matching the old
+        // behaviour of always producing a JSON index. Not needed in split mode: there
+        // the manifest is the contract, and an empty manifest is a valid answer.
+        shards.insert(String::new(), new_shard());
+    }
+    let mut doc_counts: AHashMap<String, usize> = AHashMap::new();
 
     for (_, section) in &library.sections {
-        if section.lang == lang {
-            add_section_to_index(&mut index, section, library, &language_options.search, lang);
+        if section.lang != lang {
+            continue;
         }
+
+        let shard_key = if search_config.split { top_level_key(&section.path) } else { String::new() };
+
+        let index = shards.entry(shard_key.clone()).or_insert_with(new_shard);
+        let added = add_section_to_index(index, section, library, search_config, lang);
+        *doc_counts.entry(shard_key).or_insert(0) += added;
     }
 
-    Ok(index.to_json())
+    // `BTreeMap` iterates in key order, so shards (and the manifest built from them)
+    // come out in a deterministic order across builds of the same content.
+    Ok(shards
+        .into_iter()
+        .map(|(key, index)| {
+            let num_documents = doc_counts.get(&key).copied().unwrap_or(0);
+            SearchIndexShard { json: index.to_json(), num_documents, key }
+        })
+        .collect())
+}
+
+/// Builds the manifest for `search.split`: one entry per shard mapping its section key
+/// to the filename it should be written to (see [`shard_filename`]) and its document
+/// count, so a client can decide which shard(s) to fetch before downloading any of them.
+pub fn build_manifest(lang: &str, shards: &[SearchIndexShard]) -> String {
+    let manifest: Vec<_> = shards
+        .iter()
+        .map(|shard| {
+            libs::serde_json::json!({
+                "key": shard.key,
+                "file": shard_filename(lang, &shard.key),
+                "num_documents": shard.num_documents,
+            })
+        })
+        .collect();
+    libs::serde_json::Value::Array(manifest).to_string()
 }
 
+/// The filename a shard should be written to: `search_index.<lang>.json` for the
+/// whole-site shard (empty key), `search_index.<lang>.<key>.json` otherwise.
+pub fn shard_filename(lang: &str, key: &str) -> String {
+    if key.is_empty() {
+        format!("search_index.{}.json", lang)
+    } else {
+        format!("search_index.{}.{}.json", lang, key)
+    }
+}
+
+/// The top-level ancestor segment of a section path, used to group sections/pages
+/// into shards when `search.split` is on. The root section (an empty path) and any
+/// section that otherwise doesn't start with a path segment fall back to `"_index"`.
+fn top_level_key(path: &str) -> String {
+    match path.trim_matches('/').split('/').next() {
+        Some(segment) if !segment.is_empty() => segment.to_string(),
+        _ => "_index".to_string(),
+    }
+}
+
+/// Adds `section` and its direct pages to `index`, returning how many documents were
+/// added so callers can keep a per-shard document count.
 fn add_section_to_index(
     index: &mut Index,
     section: &Section,
     library: &Library,
     search_config: &Search,
     lang: &str,
-) {
+) -> usize {
     if !section.meta.in_search_index {
-        return;
+        return 0;
     }
 
+    let mut num_documents = 0;
+
     // Don't index redirecting sections
     if section.meta.redirect_to.is_none() {
         index.add_doc(
@@ -159,12 +356,15 @@ fn add_section_to_index(
                 search_config,
                 &section.meta.title,
                 &section.meta.description,
+                // Sections don't have a date of their own.
+                &None,
                 &section.path,
                 &section.content,
                 vec![],
                 vec![],
             ),
         );
+        num_documents += 1;
     }
 
     for key in &section.pages {
@@ -182,13 +382,17 @@ fn add_section_to_index(
                 search_config,
                 &page.meta.title,
                 &page.meta.description,
+                &page.meta.date,
                 &page.path,
                 &page.content,
                 categories,
                 tags,
             ),
         );
+        num_documents += 1;
     }
+
+    num_documents
 }
 
 fn get_categories_and_tags(
@@ -258,7 +462,7 @@ mod tests {
         let path = "/a/page/".to_string();
         let content = "Some content".to_string();
 
-        let res = fill_index(&config.search, &title, &description, &path, &content, vec![], vec![]);
+        let res = fill_index(&config.search, &title, &description, &None, &path, &content, vec![], vec![]);
         assert_eq!(res.len(), 2);
         assert_eq!(res[0], title.unwrap());
         assert_eq!(res[1], content);
@@ -273,7 +477,7 @@ mod tests {
         let path = "/a/page/".to_string();
         let content = "Some content".to_string();
 
-        let res = fill_index(&config.search, &title, &description, &path, &content, vec![], vec![]);
+        let res = fill_index(&config.search, &title, &description, &None, &path, &content, vec![], vec![]);
         assert_eq!(res.len(), 3);
         assert_eq!(res[0], title.unwrap());
         assert_eq!(res[1], description.unwrap());
@@ -289,9 +493,133 @@ mod tests {
         let path = "/a/page/".to_string();
         let content = "Some content".to_string();
 
-        let res = fill_index(&config.search, &title, &description, &path, &content, vec![], vec![]);
+        let res = fill_index(&config.search, &title, &description, &None, &path, &content, vec![], vec![]);
         assert_eq!(res.len(), 2);
         assert_eq!(res[0], title.unwrap());
-        assert_eq!(res[1], content[..5]);
+        // Stops before the word boundary rather than splitting "content" in half.
+        assert_eq!(res[1], "Some");
+    }
+
+    #[test]
+    fn truncate_at_word_boundary_does_not_split_unicode_graphemes() {
+        // "é" here is decomposed into 'e' + a combining acute accent, i.e. two chars
+        // but a single grapheme cluster: a naive char-based truncation would split it.
+        let content = "café cafe\u{301} du coin";
+        let res = truncate_at_word_boundary(content, 9);
+        assert_eq!(res, "café cafe\u{301}");
+    }
+
+    #[test]
+    fn truncate_at_word_boundary_keeps_whole_text_when_shorter_than_length() {
+        let res = truncate_at_word_boundary("short", 100);
+        assert_eq!(res, "short");
+    }
+
+    #[test]
+    fn can_fill_index_snippet() {
+        let mut config = Config::default();
+        config.search.snippet = true;
+        config.search.snippet_length = Some(9);
+        let title = Some("A title".to_string());
+        let description = None;
+        let path = "/a/page/".to_string();
+        let content = "Some  content\nwith   messy   whitespace".to_string();
+
+        let res = fill_index(&config.search, &title, &description, &None, &path, &content, vec![], vec![]);
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[0], title.unwrap());
+        assert_eq!(res[1], content);
+        assert_eq!(res[2], "Some");
+    }
+
+    #[test]
+    fn code_tokenizer_keeps_underscores_and_lowercases() {
+        let res = code_tokenizer("my_function(FooBar, x-y)");
+        assert_eq!(res, vec!["my_function", "foobar", "x", "y"]);
+    }
+
+    #[test]
+    fn can_fill_index_code_blocks() {
+        let mut config = Config::default();
+        config.search.include_code_blocks = true;
+        let title = Some("A title".to_string());
+        let description = None;
+        let path = "/a/page/".to_string();
+        let content =
+            "<p>Call it like</p><pre><code>my_function(1)</code></pre><p>done</p>".to_string();
+
+        let res = fill_index(&config.search, &title, &description, &None, &path, &content, vec![], vec![]);
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[0], title.unwrap());
+        // The code block is pulled out into its own field and stripped from `body`.
+        assert_eq!(res[1], "Call it likedone");
+        assert_eq!(res[2], "my_function(1)");
+    }
+
+    #[test]
+    fn top_level_key_takes_first_path_segment() {
+        assert_eq!(top_level_key("docs/guide/intro"), "docs");
+        assert_eq!(top_level_key("/docs/guide/intro/"), "docs");
+        assert_eq!(top_level_key("blog"), "blog");
+        assert_eq!(top_level_key(""), "_index");
+    }
+
+    #[test]
+    fn shard_filename_omits_key_for_the_whole_site_shard() {
+        assert_eq!(shard_filename("en", ""), "search_index.en.json");
+        assert_eq!(shard_filename("en", "docs"), "search_index.en.docs.json");
+    }
+
+    #[test]
+    fn can_build_manifest() {
+        let shards = vec![
+            SearchIndexShard { key: "blog".to_string(), json: "{}".to_string(), num_documents: 3 },
+            SearchIndexShard { key: "docs".to_string(), json: "{}".to_string(), num_documents: 12 },
+        ];
+
+        let manifest = build_manifest("en", &shards);
+        let parsed: libs::serde_json::Value = libs::serde_json::from_str(&manifest).unwrap();
+
+        assert_eq!(
+            parsed,
+            libs::serde_json::json!([
+                {"key": "blog", "file": "search_index.en.blog.json", "num_documents": 3},
+                {"key": "docs", "file": "search_index.en.docs.json", "num_documents": 12},
+            ])
+        );
+    }
+
+    #[test]
+    fn can_build_fields_with_stored_only_metadata() {
+        let mut config = Config::default();
+        config.search.include_title = false;
+        config.search.include_description = false;
+        config.search.include_content = false;
+        config.search.store_title = true;
+        config.search.store_description = true;
+        config.search.store_date = true;
+
+        let index = build_fields(&config.search, IndexBuilder::new()).build();
+        assert_eq!(index.get_fields(), vec!["title", "description", "date"]);
+    }
+
+    #[test]
+    fn can_fill_index_stored_only_metadata() {
+        let mut config = Config::default();
+        config.search.include_title = false;
+        config.search.include_description = false;
+        config.search.include_content = false;
+        config.search.store_title = true;
+        config.search.store_description = true;
+        config.search.store_date = true;
+        let title = Some("A title".to_string());
+        let description = Some("A description".to_string());
+        let date = Some("2024-01-01".to_string());
+        let path = "/a/page/".to_string();
+        let content = "Some content".to_string();
+
+        let res =
+            fill_index(&config.search, &title, &description, &date, &path, &content, vec![], vec![]);
+        assert_eq!(res, vec!["A title".to_string(), "A description".to_string(), "2024-01-01".to_string()]);
     }
 }